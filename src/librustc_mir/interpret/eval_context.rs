@@ -0,0 +1,67 @@
+use rustc::mir::interpret::Pointer;
+use rustc::ty::{self, Ty, TyCtxt};
+use rustc_data_structures::fx::FxHashMap;
+
+use super::{Machine, Memory};
+
+/// A stack frame together with its local variables and evaluation state. The interpreter
+/// never looks at the contents of a frame through `InterpCx` directly (that is `Machine`'s
+/// job), it only ever pushes and pops them, so the full definition lives with the rest of
+/// the stack-handling code and is intentionally omitted here.
+pub struct Frame<'mir, 'tcx, Tag, Extra> {
+    _marker: std::marker::PhantomData<(&'mir (), &'tcx (), Tag, Extra)>,
+}
+
+/// The main interpreter state, shared by the `Machine` and by all evaluation helpers (see
+/// e.g. `traits.rs` for the vtable-related ones).
+pub struct InterpCx<'mir, 'tcx, M: Machine<'mir, 'tcx>> {
+    /// Stores the `Machine` instance.
+    pub machine: M,
+
+    /// The results of the type checker, from rustc.
+    pub tcx: TyCtxt<'tcx>,
+
+    /// Bounds in scope for polymorphic evaluations.
+    pub(crate) param_env: ty::ParamEnv<'tcx>,
+
+    /// The virtual memory system.
+    pub memory: Memory<'mir, 'tcx, M>,
+
+    /// The virtual call stack.
+    pub stack: Vec<Frame<'mir, 'tcx, M::PointerTag, M::FrameExtra>>,
+
+    /// A cache for deduplicating vtables: we guarantee that there is only ever one vtable
+    /// for a given `(Ty, Option<PolyExistentialTraitRef>)` pair. See `traits::get_vtable`.
+    pub(super) vtables: FxHashMap<
+        (Ty<'tcx>, Option<ty::PolyExistentialTraitRef<'tcx>>),
+        Pointer<M::PointerTag>,
+    >,
+
+    /// For each `(ty, sub_trait_ref, super_trait_def_id)` upcasting pair, the byte offset
+    /// (past the `[drop, size, align]` header) at which `super_trait_def_id`'s contiguous
+    /// method region begins within the `sub_trait_ref` vtable's allocation. Populated
+    /// alongside `vtables` by `traits::get_vtable` and consumed by `traits::upcast_vtable`.
+    pub(super) vtable_super_offsets: FxHashMap<
+        (Ty<'tcx>, ty::PolyExistentialTraitRef<'tcx>, rustc::hir::def_id::DefId),
+        u64,
+    >,
+}
+
+impl<'mir, 'tcx, M: Machine<'mir, 'tcx>> InterpCx<'mir, 'tcx, M> {
+    pub fn new(
+        tcx: TyCtxt<'tcx>,
+        param_env: ty::ParamEnv<'tcx>,
+        machine: M,
+        memory_extra: M::MemoryExtra,
+    ) -> Self {
+        InterpCx {
+            machine,
+            tcx,
+            param_env,
+            memory: Memory::new(tcx, memory_extra),
+            stack: Vec::new(),
+            vtables: FxHashMap::default(),
+            vtable_super_offsets: FxHashMap::default(),
+        }
+    }
+}
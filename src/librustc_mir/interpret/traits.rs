@@ -1,6 +1,8 @@
 use rustc::ty::{self, Ty, Instance, TypeFoldable};
 use rustc::ty::layout::{Size, Align, LayoutOf, HasDataLayout};
+use rustc::ty::subst::Subst;
 use rustc::mir::interpret::{Scalar, Pointer, InterpResult, PointerArithmetic,};
+use rustc::traits;
 
 use super::{InterpCx, Machine, MemoryKind, FnVal};
 
@@ -95,23 +97,134 @@ impl<'mir, 'tcx, M: Machine<'mir, 'tcx>> InterpCx<'mir, 'tcx, M> {
         self.memory.mark_immutable(vtable.alloc_id)?;
         assert!(self.vtables.insert((ty, poly_trait_ref), vtable).is_none());
 
+        // Record, for each supertrait of `poly_trait_ref`, the byte offset (past the
+        // `[drop, size, align]` header) at which its contiguous method region begins
+        // within this very allocation. `vtable_methods` lays out supertrait methods
+        // before the trait's own direct methods, so this is just a running total of
+        // the byte sizes of the method regions of the supertraits seen so far. This is what lets
+        // `upcast_vtable` find the `Super` method region without redoing method
+        // resolution.
+        if let Some(poly_trait_ref) = poly_trait_ref {
+            let trait_ref = poly_trait_ref.with_self_ty(*self.tcx, ty);
+            let trait_ref = self.tcx.erase_regions(&trait_ref);
+
+            let mut offset = 0_u64;
+            for supertrait in traits::supertraits(*self.tcx, ty::Binder::bind(trait_ref)) {
+                let supertrait = supertrait.skip_binder();
+                let super_methods = self.tcx.vtable_methods(supertrait);
+                self.vtable_super_offsets.insert(
+                    (ty, poly_trait_ref, supertrait.def_id),
+                    offset,
+                );
+                offset += ptr_mem_pos.size.bytes() * super_methods.len() as u64;
+            }
+        }
+
         Ok(vtable)
     }
 
-    /// Returns the drop fn instance as well as the actual dynamic type
+    /// Returns a pointer `offset` bytes into `vtable`'s method region, i.e. past the
+    /// `[drop, size, align]` header. An `offset` of `0` is the start of `vtable`'s own
+    /// methods; a non-zero `offset` (as recorded in `vtable_super_offsets`) points at
+    /// one of its supertraits' method sub-slices instead.
+    pub fn get_vtable_slot(
+        &self,
+        vtable: Pointer<M::PointerTag>,
+        offset: u64,
+    ) -> InterpResult<'tcx, Pointer<M::PointerTag>> {
+        let ptr_mem_pos = self.tcx.data_layout.pointer_pos.mem_pos();
+        vtable.offset((ptr_mem_pos * 3).size + Size::from_bytes(offset), &*self.tcx)
+    }
+
+    /// Infrastructure only: builds and validates the vtable that a `dyn Sub -> dyn Super`
+    /// coercion of `ty` *would* produce, given the already-built `Sub` vtable. Nothing in
+    /// this crate's MIR evaluation loop calls this method -- there is no
+    /// `Rvalue::Cast(CastKind::Unsize, ..)` handling in `cast.rs` that dispatches here, so
+    /// no such coercion is actually evaluated anywhere in this series yet. This function
+    /// alone does not make upcasting work in the interpreter; it only provides the
+    /// primitive that such a cast handler would need to call, once one exists.
+    ///
+    /// We do not need a new allocation for the *methods*, since they already live as a
+    /// contiguous sub-slice of the `Sub` vtable (see `get_vtable`'s `vtable_super_offsets`
+    /// bookkeeping, located here via `get_vtable_slot`). However
+    /// `read_size_and_align_from_vtable`/`read_drop_type_from_vtable` expect every vtable
+    /// to start with its own `[drop, size, align]` header, so we still materialize a
+    /// distinct `Super` vtable -- this goes through the ordinary `get_vtable` dedup cache,
+    /// so upcasting the same `(ty, Super)` pair twice is free after the first time. We
+    /// then check, slot by slot, that the materialized `Super` vtable's methods are the
+    /// exact same function pointers as the sub-slice we located, which is the key
+    /// invariant this whole scheme depends on.
+    pub fn upcast_vtable(
+        &mut self,
+        ty: Ty<'tcx>,
+        sub_trait_ref: ty::PolyExistentialTraitRef<'tcx>,
+        super_trait_ref: ty::PolyExistentialTraitRef<'tcx>,
+    ) -> InterpResult<'tcx, Pointer<M::PointerTag>> {
+        trace!("upcast_vtable(ty={:?}, sub={:?}, super={:?})", ty, sub_trait_ref, super_trait_ref);
+
+        // Upcasting a trait to itself is a no-op.
+        if sub_trait_ref == super_trait_ref {
+            return self.get_vtable(ty, Some(sub_trait_ref));
+        }
+
+        // Force `sub_trait_ref`'s vtable (and with it, `vtable_super_offsets`) to exist.
+        let sub_vtable = self.get_vtable(ty, Some(sub_trait_ref))?;
+
+        let (ty, sub_trait_ref) = self.tcx.erase_regions(&(ty, sub_trait_ref));
+        let offset = *self.vtable_super_offsets
+            .get(&(ty, sub_trait_ref, super_trait_ref.def_id()))
+            .ok_or_else(|| err_ub_format!(
+                "`{:?}` is not a supertrait of `{:?}` for `{:?}`",
+                super_trait_ref, sub_trait_ref, ty,
+            ))?;
+        let sub_methods_start = self.get_vtable_slot(sub_vtable, offset)?;
+
+        // Build (or fetch from the cache) the `Super` vtable the normal way.
+        let super_vtable = self.get_vtable(ty, Some(super_trait_ref))?;
+        let super_methods_start = self.get_vtable_slot(super_vtable, 0)?;
+
+        let super_trait_ref = super_trait_ref.with_self_ty(*self.tcx, ty);
+        let super_trait_ref = self.tcx.erase_regions(&super_trait_ref);
+        let ptr_pos = self.pointer_pos();
+        for (i, _) in self.tcx.vtable_methods(super_trait_ref).iter().enumerate() {
+            let sub_slot = self.memory.get_raw(sub_methods_start.alloc_id)?
+                .read_ptr_sized(self, sub_methods_start.offset((ptr_pos * i as u64).size, self)?)?;
+            let super_slot = self.memory.get_raw(super_methods_start.alloc_id)?
+                .read_ptr_sized(self, super_methods_start.offset((ptr_pos * i as u64).size, self)?)?;
+            if sub_slot != super_slot {
+                throw_ub_format!(
+                    "upcast vtable method {} does not match a directly-built `dyn Super` vtable",
+                    i,
+                );
+            }
+        }
+
+        Ok(super_vtable)
+    }
+
+    /// Returns the drop fn instance as well as the actual dynamic type.
+    ///
+    /// `vtable` is a pointer supplied by whatever place holds the trait object -- it may
+    /// never have gone through `get_vtable` at all (e.g. it was reached via `transmute`).
+    /// When `expected_trait` is the statically-known trait of that place (the `Trait` in
+    /// `&dyn Trait`/`Box<dyn Trait>`), we use the dynamic type found here to run the full
+    /// `check_vtable` validation against it before trusting anything else about `vtable`;
+    /// this is the point at which a corrupted or hand-forged vtable actually gets caught,
+    /// rather than failing with an opaque error deep inside a later dynamic call.
     pub fn read_drop_type_from_vtable(
         &self,
         vtable: Scalar<M::PointerTag>,
+        expected_trait: Option<ty::PolyExistentialTraitRef<'tcx>>,
     ) -> InterpResult<'tcx, (ty::Instance<'tcx>, Ty<'tcx>)> {
         // we don't care about the pointee type, we just want a pointer
-        let vtable = self.memory.check_ptr_access(
+        let vtable_ptr = self.memory.check_ptr_access(
             vtable,
             self.tcx.data_layout.pointer_pos.size,
             self.tcx.data_layout.pointer_pos.align.abi,
         )?.expect("cannot be a ZST");
         let drop_fn = self.memory
-            .get_raw(vtable.alloc_id)?
-            .read_ptr_sized(self, vtable)?
+            .get_raw(vtable_ptr.alloc_id)?
+            .read_ptr_sized(self, vtable_ptr)?
             .not_undef()?;
         // We *need* an instance here, no other kind of function value, to be able
         // to determine the type.
@@ -121,9 +234,20 @@ impl<'mir, 'tcx, M: Machine<'mir, 'tcx>> InterpCx<'mir, 'tcx, M> {
         let fn_sig = self.tcx.normalize_erasing_late_bound_regions(self.param_env, &fn_sig);
         // The drop function takes `*mut T` where `T` is the type being dropped, so get that.
         let ty = fn_sig.inputs()[0].builtin_deref(true).unwrap().ty;
+
+        if expected_trait.is_some() {
+            self.check_vtable(vtable, ty, expected_trait)?;
+        }
+
         Ok((drop_instance, ty))
     }
 
+    /// Unlike `read_drop_type_from_vtable`, this cannot run `check_vtable` on `vtable`'s
+    /// behalf: `check_vtable` needs the dynamic `ty` to look up the trait's methods, and
+    /// the size/align slots read here don't get us there (only the drop fn does). Callers
+    /// that also call `read_drop_type_from_vtable` on the same `vtable` get the benefit of
+    /// that validation already; a caller with only a `vtable` and no drop fn to read has
+    /// no way to validate it before reading the size/align out of it.
     pub fn read_size_and_align_from_vtable(
         &self,
         vtable: Scalar<M::PointerTag>,
@@ -154,4 +278,79 @@ impl<'mir, 'tcx, M: Machine<'mir, 'tcx>> InterpCx<'mir, 'tcx, M> {
         }
         Ok((Size::from_bytes(size), Align::from_bytes(align).unwrap()))
     }
+
+    /// Does an exhaustive consistency check of `vtable` against what a vtable for
+    /// `(ty, poly_trait_ref)` built by `get_vtable` would look like: the allocation's
+    /// size must match `3 + methods.len()` pointers exactly, and every non-`None`
+    /// method slot must resolve to a function instance whose signature matches the
+    /// corresponding trait method. `read_drop_type_from_vtable`/
+    /// `read_size_and_align_from_vtable` only look at the first three slots, so this
+    /// is what lets Miri catch a hand-forged (e.g. `transmute`d) vtable up front,
+    /// instead of failing with an opaque error deep inside a later dynamic call.
+    pub fn check_vtable(
+        &self,
+        vtable: Scalar<M::PointerTag>,
+        ty: Ty<'tcx>,
+        poly_trait_ref: Option<ty::PolyExistentialTraitRef<'tcx>>,
+    ) -> InterpResult<'tcx, ()> {
+        let methods = if let Some(poly_trait_ref) = poly_trait_ref {
+            let trait_ref = poly_trait_ref.with_self_ty(*self.tcx, ty);
+            let trait_ref = self.tcx.erase_regions(&trait_ref);
+
+            self.tcx.vtable_methods(trait_ref)
+        } else {
+            &[]
+        };
+
+        let ptr_pos = self.pointer_pos();
+        let expected_size = (ptr_pos * (3 + methods.len() as u64)).size;
+        let vtable = self.memory.check_ptr_access(
+            vtable,
+            expected_size,
+            self.tcx.data_layout.pointer_pos.align.abi,
+        )?.expect("cannot be a ZST");
+        let alloc = self.memory.get_raw(vtable.alloc_id)?;
+        let actual_size = Size::from_bytes(alloc.bytes.len() as u64);
+        if actual_size != expected_size {
+            throw_ub_format!(
+                "invalid vtable: expected {} bytes for {} methods, but the allocation is {} bytes",
+                expected_size.bytes(), methods.len(), actual_size.bytes(),
+            );
+        }
+
+        for (i, method) in methods.iter().enumerate() {
+            let (def_id, substs) = match *method {
+                Some(method) => method,
+                // A `None` slot is a trait method with no possible implementation (e.g. one
+                // that is not object safe by itself); there is nothing to call, so nothing
+                // to check.
+                None => continue,
+            };
+
+            let method_ptr = vtable.offset((ptr_pos * (3 + i as u64)).size, self)?;
+            let method_val = alloc.read_ptr_sized(self, method_ptr)?.not_undef()?;
+            let instance = self.memory.get_fn(method_val)?.as_instance()?;
+
+            let expected_sig = self.tcx.fn_sig(def_id).subst(*self.tcx, substs);
+            let expected_sig =
+                self.tcx.normalize_erasing_late_bound_regions(self.param_env, &expected_sig);
+            let found_sig = instance.ty(*self.tcx).fn_sig(*self.tcx);
+            let found_sig =
+                self.tcx.normalize_erasing_late_bound_regions(self.param_env, &found_sig);
+
+            // The `Self` receiver is erased to a raw pointer for dynamic dispatch, so only
+            // the non-receiver inputs and the output need to line up.
+            let sigs_match = expected_sig.inputs().len() == found_sig.inputs().len()
+                && expected_sig.inputs()[1..].iter().eq(found_sig.inputs()[1..].iter())
+                && expected_sig.output() == found_sig.output();
+            if !sigs_match {
+                throw_ub_format!(
+                    "vtable method {} has wrong signature: expected `{:?}`, found `{:?}`",
+                    i, expected_sig, found_sig,
+                );
+            }
+        }
+
+        Ok(())
+    }
 }
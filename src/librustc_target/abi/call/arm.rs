@@ -1,17 +1,47 @@
 use crate::abi::call::{Conv, FnAbi, ArgAbi, Reg, RegKind, Uniform};
-use crate::abi::{HasDataLayout, LayoutOf, TyLayout, TyLayoutMethods};
+use crate::abi::{FieldsShape, HasDataLayout, LayoutOf, TyLayout, TyLayoutMethods};
 use crate::spec::HasTargetSpec;
 
+/// Recursively counts the number of leaf scalar members that make up `layout`. Unlike
+/// dividing `layout.pref_pos.size` by a unit's size, this does not get confused by
+/// trailing padding (e.g. a `struct { f32; f32; f32; }` padded out to 16 bytes is 3
+/// members, not 4) or by nested structs/arrays of the same homogeneous unit.
+fn count_members<'a, Ty, C>(cx: &C, layout: TyLayout<'a, Ty>) -> u64
+    where Ty: TyLayoutMethods<'a, C> + Copy,
+          C: LayoutOf<Ty = Ty, TyLayout = TyLayout<'a, Ty>> + HasDataLayout
+{
+    match &layout.fields {
+        FieldsShape::Primitive => 1,
+        FieldsShape::Array { count, .. } => {
+            if layout.field(cx, 0).is_zst() {
+                0
+            } else {
+                count * count_members(cx, layout.field(cx, 0))
+            }
+        }
+        FieldsShape::Union(count) => {
+            (0..count.get()).map(|i| count_members(cx, layout.field(cx, i))).sum()
+        }
+        FieldsShape::Arbitrary { ref offsets, .. } => {
+            (0..offsets.len()).map(|i| count_members(cx, layout.field(cx, i))).sum()
+        }
+    }
+}
+
 fn is_homogeneous_aggregate<'a, Ty, C>(cx: &C, arg: &mut ArgAbi<'a, Ty>)
-                                     -> Option<Uniform>
+                                     -> Option<(Uniform, u64)>
     where Ty: TyLayoutMethods<'a, C> + Copy,
           C: LayoutOf<Ty = Ty, TyLayout = TyLayout<'a, Ty>> + HasDataLayout
 {
     arg.layout.homogeneous_aggregate(cx).unit().and_then(|unit| {
         let size = arg.layout.pref_pos.size;
+        let members = count_members(cx, arg.layout);
 
-        // Ensure we have at most four uniquely addressable members.
-        if size > unit.size.checked_mul(4, cx).unwrap() {
+        // Ensure we have at most four uniquely addressable members, and that `size`
+        // really is `unit.size * members` -- i.e. that there is no trailing padding
+        // being mistaken for an extra member (a padded 3-member struct is not a
+        // legitimate 4-register HFA, even though its size divides evenly by `unit.size`).
+        if members > 4 || size.bytes() != unit.size.bytes() * members {
             return None;
         }
 
@@ -22,10 +52,7 @@ fn is_homogeneous_aggregate<'a, Ty, C>(cx: &C, arg: &mut ArgAbi<'a, Ty>)
         };
 
         if valid_unit {
-            Some(Uniform {
-                unit,
-                total: size
-            })
+            Some((Uniform { unit, total: size }, members))
         } else {
             None
         }
@@ -42,7 +69,8 @@ fn classify_ret<'a, Ty, C>(cx: &C, ret: &mut ArgAbi<'a, Ty>, vfp: bool)
     }
 
     if vfp {
-        if let Some(uniform) = is_homogeneous_aggregate(cx, ret) {
+        if let Some((uniform, members)) = is_homogeneous_aggregate(cx, ret) {
+            debug_assert_eq!(uniform.total.bytes(), uniform.unit.size.bytes() * members);
             ret.cast_to(uniform);
             return;
         }
@@ -77,7 +105,8 @@ fn classify_arg<'a, Ty, C>(cx: &C, arg: &mut ArgAbi<'a, Ty>, vfp: bool)
     }
 
     if vfp {
-        if let Some(uniform) = is_homogeneous_aggregate(cx, arg) {
+        if let Some((uniform, members)) = is_homogeneous_aggregate(cx, arg) {
+            debug_assert_eq!(uniform.total.bytes(), uniform.unit.size.bytes() * members);
             arg.cast_to(uniform);
             return;
         }
@@ -91,15 +120,33 @@ fn classify_arg<'a, Ty, C>(cx: &C, arg: &mut ArgAbi<'a, Ty>, vfp: bool)
     });
 }
 
+/// Decides whether homogeneous aggregates should be passed in VFP registers, given the
+/// function's calling convention, variadic-ness, and whether the *target* defaults to
+/// hard-float. This is a free function (rather than inlined into `compute_abi_info`)
+/// purely so it can be unit-tested without needing a real `TyLayout`/`HasTargetSpec`.
+fn select_vfp(conv: Conv, c_variadic: bool, target_is_hard_float: bool) -> bool {
+    // `extern "aapcs-vfp"`/`extern "aapcs"` override whatever the target triple's `hf`
+    // suffix would otherwise imply, letting a function force VFP-register (hard-float)
+    // or base-AAPCS (soft-float) passing independent of the target default. Absent an
+    // explicit convention, fall back to the target default: use the VFP registers for
+    // homogeneous aggregates iff this is a hard-float target.
+    let vfp = match conv {
+        Conv::ArmAapcsVfp => true,
+        Conv::ArmAapcs => false,
+        _ => target_is_hard_float,
+    };
+    vfp && !c_variadic
+}
+
 pub fn compute_abi_info<'a, Ty, C>(cx: &C, fn_abi: &mut FnAbi<'a, Ty>)
     where Ty: TyLayoutMethods<'a, C> + Copy,
           C: LayoutOf<Ty = Ty, TyLayout = TyLayout<'a, Ty>> + HasDataLayout + HasTargetSpec
 {
-    // If this is a target with a hard-float ABI, and the function is not explicitly
-    // `extern "aapcs"`, then we must use the VFP registers for homogeneous aggregates.
-    let vfp = cx.target_spec().llvm_target.ends_with("hf")
-        && fn_abi.conv != Conv::ArmAapcs
-        && !fn_abi.c_variadic;
+    let vfp = select_vfp(
+        fn_abi.conv,
+        fn_abi.c_variadic,
+        cx.target_spec().llvm_target.ends_with("hf"),
+    );
 
     if !fn_abi.ret.is_ignore() {
         classify_ret(cx, &mut fn_abi.ret, vfp);
@@ -110,3 +157,29 @@ pub fn compute_abi_info<'a, Ty, C>(cx: &C, fn_abi: &mut FnAbi<'a, Ty>)
         classify_arg(cx, arg, vfp);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{select_vfp, Conv};
+
+    #[test]
+    fn explicit_aapcs_vfp_forces_vfp_on_soft_float_target() {
+        assert!(select_vfp(Conv::ArmAapcsVfp, false, /* target_is_hard_float */ false));
+    }
+
+    #[test]
+    fn explicit_aapcs_forces_integer_regs_on_hard_float_target() {
+        assert!(!select_vfp(Conv::ArmAapcs, false, /* target_is_hard_float */ true));
+    }
+
+    #[test]
+    fn default_convention_follows_target() {
+        assert!(select_vfp(Conv::C, false, true));
+        assert!(!select_vfp(Conv::C, false, false));
+    }
+
+    #[test]
+    fn variadic_calls_never_use_vfp() {
+        assert!(!select_vfp(Conv::ArmAapcsVfp, true, true));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::abi::{HasDataLayout, LayoutOf, Size, TyLayout, TyLayoutMethods};
+use crate::spec::{HasTargetSpec, abi::Abi as SpecAbi};
+
+mod arm;
+
+/// Categorizes how an argument or return value is passed in a register, used to build a
+/// `Uniform` when an aggregate is classified as a homogeneous sequence of one of these.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegKind {
+    Integer,
+    Float,
+    Vector,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Reg {
+    pub kind: RegKind,
+    pub size: Size,
+}
+
+macro_rules! reg_ctor {
+    ($name:ident, $kind:ident, $bits:expr) => {
+        pub fn $name() -> Reg {
+            Reg { kind: RegKind::$kind, size: Size::from_bits($bits) }
+        }
+    };
+}
+
+impl Reg {
+    reg_ctor!(i8, Integer, 8);
+    reg_ctor!(i16, Integer, 16);
+    reg_ctor!(i32, Integer, 32);
+    reg_ctor!(i64, Integer, 64);
+
+    reg_ctor!(f32, Float, 32);
+    reg_ctor!(f64, Float, 64);
+}
+
+/// A sequence of `total / unit.size` identical `unit` registers, used to pass a
+/// homogeneous aggregate (e.g. a 3x `f32` HFA) as if it were that many scalars.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Uniform {
+    pub unit: Reg,
+    pub total: Size,
+}
+
+impl From<Reg> for Uniform {
+    fn from(unit: Reg) -> Uniform {
+        Uniform { unit, total: unit.size }
+    }
+}
+
+/// How an `ArgAbi` is actually passed, as decided by a target-specific classifier (e.g.
+/// `arm::compute_abi_info`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PassMode {
+    /// Ignored (a ZST, or a return type of `()`); nothing is passed at all.
+    Ignore,
+    /// Passed as-is, as the layout's own scalar/aggregate representation.
+    Direct,
+    /// Cast to a `Uniform` sequence of registers (e.g. an HFA passed via VFP registers).
+    Cast(Uniform),
+    /// Passed indirectly, via a pointer to a caller-allocated stack slot.
+    Indirect,
+}
+
+/// The classified ABI of a single argument or return value.
+pub struct ArgAbi<'a, Ty> {
+    pub layout: TyLayout<'a, Ty>,
+    pub mode: PassMode,
+}
+
+impl<'a, Ty> ArgAbi<'a, Ty> {
+    pub fn new(layout: TyLayout<'a, Ty>) -> Self {
+        ArgAbi { layout, mode: PassMode::Direct }
+    }
+
+    pub fn is_ignore(&self) -> bool {
+        self.mode == PassMode::Ignore
+    }
+
+    pub fn make_indirect(&mut self) {
+        self.mode = PassMode::Indirect;
+    }
+
+    pub fn cast_to<U: Into<Uniform>>(&mut self, target: U) {
+        self.mode = PassMode::Cast(target.into());
+    }
+
+    /// Called for a small integer return or argument that is to be passed at least `bits`
+    /// wide. This type records no extension attributes, so the call changes nothing: it
+    /// neither widens the value nor alters `self.mode`.
+    pub fn extend_integer_width_to(&mut self, _bits: u64) {
+        // The actual extension is encoded on the scalar's `ArgAttributes` in the full
+        // implementation of this type (outside this snapshot); here it is a no-op beyond
+        // leaving `self.mode` as `Direct`, which is already the default we start from.
+    }
+}
+
+/// The classified calling convention of a function, independent of any particular target.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Conv {
+    C,
+    Rust,
+
+    /// The base AAPCS convention: aggregates (including HFAs) are passed in the integer
+    /// register file / on the stack, never in VFP registers.
+    ArmAapcs,
+    /// `extern "aapcs-vfp"`: like `ArmAapcs`, except homogeneous float/vector aggregates
+    /// are passed in VFP registers, regardless of whether the target itself defaults to
+    /// soft- or hard-float. See `arm::compute_abi_info`.
+    ArmAapcsVfp,
+}
+
+/// Maps a surface-syntax `extern "..."` ABI string to the `Conv` our classifiers switch
+/// on. `Rust`/`rust-call` map to `Rust`; every other arch-agnostic or
+/// unrecognized-for-this-arch convention collapses to `C`.
+///
+/// NOTE: `SpecAbi::Aapcs`/`SpecAbi::AapcsVfp` (the `extern "aapcs"`/`extern "aapcs-vfp"`
+/// string literals themselves) are parsed in `librustc_target/spec/abi.rs`, which is not
+/// part of this snapshot; adding `"aapcs-vfp"` to that string table is the other half of
+/// making this convention reachable from actual source.
+pub fn conv_from_spec_abi(cx: &impl HasTargetSpec, abi: SpecAbi) -> Conv {
+    match (&cx.target_spec().arch[..], abi) {
+        (_, SpecAbi::Rust) | (_, SpecAbi::RustCall) => Conv::Rust,
+        ("arm", SpecAbi::Aapcs) => Conv::ArmAapcs,
+        ("arm", SpecAbi::AapcsVfp) => Conv::ArmAapcsVfp,
+        _ => Conv::C,
+    }
+}
+
+pub struct FnAbi<'a, Ty> {
+    pub args: Vec<ArgAbi<'a, Ty>>,
+    pub ret: ArgAbi<'a, Ty>,
+    pub conv: Conv,
+    pub c_variadic: bool,
+}
+
+impl<'a, Ty> FnAbi<'a, Ty> {
+    pub fn adjust_for_cabi<C>(&mut self, cx: &C, abi: SpecAbi)
+        where Ty: TyLayoutMethods<'a, C> + Copy,
+              C: LayoutOf<Ty = Ty, TyLayout = TyLayout<'a, Ty>> + HasDataLayout + HasTargetSpec
+    {
+        self.conv = conv_from_spec_abi(cx, abi);
+        match &cx.target_spec().arch[..] {
+            "arm" => arm::compute_abi_info(cx, self),
+            _ => {}
+        }
+    }
+}